@@ -21,22 +21,66 @@ use vst::buffer::AudioBuffer;
 use vst::plugin::{Category, Info, Plugin, PluginParameters};
 use vst::plugin_main;
 
+/// Longest delay the echo stage can be asked for; bounds the ring buffer allocation.
+const MAX_DELAY_SECONDS: f32 = 2.0;
+/// Number of per-channel echo ring buffers kept around; the plugin is currently stereo,
+/// but this leaves room to grow without re-plumbing the allocation.
+const MAX_DELAY_CHANNELS: usize = 8;
+/// Decay time constant of the peak-hold meter.
+const METER_PEAK_DECAY_SECONDS: f32 = 0.3;
+/// Time constant of the RMS meter's smoothing window.
+const METER_RMS_SECONDS: f32 = 0.3;
+
+fn amplitude_to_db_text(amplitude: f32) -> String {
+	if amplitude < 1e-5 {
+		"-inf dB".to_string()
+	} else {
+		format!("{:+.1} dB", 20.0 * amplitude.log10())
+	}
+}
+
 struct FaderEffect {
 	parameters: Arc<FaderEffectParameters>,
 	current_volume: f32,
 	current_pan: f32,
+	sample_rate: f32,
+	// Kept at f64 regardless of the sample type being processed so that `process_f64`
+	// (`f64_precision: true` in `get_info`) doesn't round-trip its extra mantissa bits
+	// through f32 on every sample.
+	delay_buffers: Vec<Vec<f64>>,
+	delay_write_pos: usize,
 }
 
 impl Default for FaderEffect {
 	fn default() -> FaderEffect {
+		let parameters = Arc::new(FaderEffectParameters::default());
+		let storage = *parameters.storage.read().unwrap();
+		let sample_rate = 44_100.0;
 		FaderEffect {
-			parameters: Arc::new(FaderEffectParameters::default()),
-			current_volume: -1.0, // these should really be reset to -1.0 whenever processing is interrupted or transport is moved etc.
-			current_pan: -1.0,
+			parameters,
+			current_volume: storage.volume,
+			current_pan: storage.pan,
+			sample_rate,
+			delay_buffers: new_delay_buffers(sample_rate),
+			delay_write_pos: 0,
 		}
 	}
 }
 
+fn new_delay_buffers(sample_rate: f32) -> Vec<Vec<f64>> {
+	let max_delay_samples = (sample_rate * MAX_DELAY_SECONDS).ceil().max(1.0) as usize;
+	vec![vec![0.0f64; max_delay_samples]; MAX_DELAY_CHANNELS]
+}
+
+fn read_delay_line(buffer: &[f64], write_pos: usize, delay_samples: f64) -> f64 {
+	let len = buffer.len();
+	let delayed_pos = (write_pos as f64 - delay_samples).rem_euclid(len as f64);
+	let index = delayed_pos as usize;
+	let frac = delayed_pos - index as f64;
+	let next_index = (index + 1) % len;
+	(1.0 - frac) * buffer[index] + frac * buffer[next_index]
+}
+
 impl FaderEffect {
 	fn process_internal<F: num_traits::Float + std::convert::From<f32>>(
 		&mut self,
@@ -47,75 +91,195 @@ impl FaderEffect {
 		let target_pan = parameters.pan;
 		let pan_taper = &PAN_LUT[std::cmp::min((parameters.pan_taper * 2.0) as usize, 1)];
 		let pan_law = &pan_taper[std::cmp::min((parameters.pan_law * 3.0) as usize, 2)];
+		let cross_feed_pan = &PAN_LUT[0][0];
+		let cross_feed = parameters.pan_mode >= 0.5;
 		let volume_lut = &*VOLUME_LUT;
 
-		let mut volume = if self.current_volume < 0.0 {
-			target_volume
-		} else {
-			self.current_volume
-		};
-		self.current_volume = target_volume;
+		let tau_seconds = parameters.tau;
+		let smoothing_coeff = (-1.0 / (tau_seconds * self.sample_rate)).exp();
+		let smoothing = 1.0 - smoothing_coeff;
 
-		let mut pan = if self.current_pan < 0.0 {
-			target_pan
-		} else {
-			self.current_pan
-		};
-		self.current_pan = target_pan;
+		let mut volume = self.current_volume;
+		let mut pan = self.current_pan;
 
 		let num_samples = buffer.samples();
 		let (inputs, outputs) = buffer.split();
 		let num_inputs = inputs.len();
 		let num_outputs = outputs.len();
 		let num_channels = std::cmp::min(num_inputs, num_outputs);
+		// The host negotiated `num_channels` from `get_info` at construction time and it stays
+		// fixed for the life of this instance (see the doc comment there), so the speaker
+		// layout used for panning must be derived from it rather than from the "Layout"
+		// parameter, which a host may keep letting the user change at runtime.
+		let speaker_layout = speaker_layout_for_channel_count(num_channels);
 		let channel_pairs = num_channels / 2;
-
-		let volume_delta = (target_volume - volume) / num_samples as f32;
-		let pan_delta = (target_pan - pan) / num_samples as f32;
+		let mono_to_stereo = cross_feed && num_inputs == 1 && num_outputs >= 2;
+
+		let delay_samples = (parameters.delay_time * MAX_DELAY_SECONDS * self.sample_rate)
+			.max(1.0)
+			.min(self.delay_buffers[0].len() as f32 - 2.0) as f64;
+		let feedback = parameters.feedback as f64;
+		let wet = parameters.mix as f64;
+		let echo_channels = std::cmp::min(num_outputs, self.delay_buffers.len());
+
+		let peak_decay: f32 = (-1.0 / (METER_PEAK_DECAY_SECONDS * self.sample_rate)).exp();
+		let rms_smoothing = 1.0 - (-1.0 / (METER_RMS_SECONDS * self.sample_rate)).exp();
+		let mut peak_left = parameters.peak_left;
+		let mut peak_right = parameters.peak_right;
+		let mut mean_square_left = parameters.rms_left * parameters.rms_left;
+		let mut mean_square_right = parameters.rms_right * parameters.rms_right;
+		drop(parameters);
 
 		for sample_idx in 0..num_samples {
-			volume = (volume + volume_delta).max(0.0).min(1.0);
-			pan = (pan + pan_delta).max(0.0).min(1.0);
+			volume = (volume + (target_volume - volume) * smoothing).max(0.0).min(1.0);
+			pan = (pan + (target_pan - pan) * smoothing).max(0.0).min(1.0);
 
 			let volume_gain: F = lookup_interpolated(volume_lut, volume).into();
-			let gain_left = volume_gain * lookup_interpolated(pan_law, pan).into();
-			let gain_right = volume_gain * lookup_interpolated(pan_law, 1.0 - pan).into();
 
-			for channel_pair in 0..channel_pairs {
-				let left_index = 2 * channel_pair;
-				let right_index = left_index + 1;
+			if mono_to_stereo {
+				let gain_left: F = lookup_interpolated(cross_feed_pan, pan).into();
+				let gain_right: F = lookup_interpolated(cross_feed_pan, 1.0 - pan).into();
+				let input = non_finite_to_zero(inputs.get(0)[sample_idx]);
 
-				let input_left = non_finite_to_zero(inputs.get(left_index)[sample_idx]);
-				let input_right = non_finite_to_zero(inputs.get(right_index)[sample_idx]);
-				let output_left = &mut outputs.get_mut(left_index)[sample_idx];
-				let output_right = &mut outputs.get_mut(right_index)[sample_idx];
+				outputs.get_mut(0)[sample_idx] = volume_gain * gain_left * input;
+				outputs.get_mut(1)[sample_idx] = volume_gain * gain_right * input;
 
-				*output_left = gain_left * input_left;
-				*output_right = gain_right * input_right;
-			}
+				for index in 2..num_outputs {
+					outputs.get_mut(index)[sample_idx] = F::zero();
+				}
+			} else if num_channels > 2 {
+				// Only reachable when the host hands us more than 2 channels. `get_info` runs
+				// at construction, while "Layout" still holds its Stereo default, so it always
+				// reports 2 in / 2 out (see the doc comment there). `speaker_layout` is derived
+				// from `num_channels` itself, so changing the "Layout" parameter cannot desync
+				// it from the channel count actually being processed.
+				let pan_azimuth = -90.0 + pan * 180.0;
+
+				for channel in 0..num_channels {
+					let input = non_finite_to_zero(inputs.get(channel)[sample_idx]);
+					let is_lfe = speaker_layout.lfe.get(channel).copied().unwrap_or(false);
+					let gain = if is_lfe {
+						volume_gain
+					} else {
+						let speaker_azimuth = speaker_layout.azimuths.get(channel).copied().unwrap_or(0.0);
+						let pan_gain: F = ring_pan_gain(pan_law, pan_azimuth, speaker_azimuth).into();
+						volume_gain * pan_gain
+					};
 
-			if 2 * channel_pairs != num_channels {
-				outputs.get_mut(num_channels - 1)[sample_idx] =
-					volume_gain * non_finite_to_zero(inputs.get(num_channels - 1)[sample_idx]);
+					outputs.get_mut(channel)[sample_idx] = gain * input;
+				}
+
+				for index in num_channels..num_outputs {
+					outputs.get_mut(index)[sample_idx] = F::zero();
+				}
+			} else {
+				let gain_left = volume_gain * lookup_interpolated(pan_law, pan).into();
+				let gain_right = volume_gain * lookup_interpolated(pan_law, 1.0 - pan).into();
+
+				for channel_pair in 0..channel_pairs {
+					let left_index = 2 * channel_pair;
+					let right_index = left_index + 1;
+
+					let input_left = non_finite_to_zero(inputs.get(left_index)[sample_idx]);
+					let input_right = non_finite_to_zero(inputs.get(right_index)[sample_idx]);
+					let output_left = &mut outputs.get_mut(left_index)[sample_idx];
+					let output_right = &mut outputs.get_mut(right_index)[sample_idx];
+
+					if cross_feed {
+						let x = if pan <= 0.5 { 2.0 * pan } else { 2.0 * pan - 1.0 };
+						let cross_gain_left: F = lookup_interpolated(cross_feed_pan, x).into();
+						let cross_gain_right: F = lookup_interpolated(cross_feed_pan, 1.0 - x).into();
+
+						if pan <= 0.5 {
+							*output_left = volume_gain * (input_left + input_right * cross_gain_left);
+							*output_right = volume_gain * input_right * cross_gain_right;
+						} else {
+							*output_left = volume_gain * input_left * cross_gain_left;
+							*output_right = volume_gain * (input_right + input_left * cross_gain_right);
+						}
+					} else {
+						*output_left = gain_left * input_left;
+						*output_right = gain_right * input_right;
+					}
+				}
+
+				if 2 * channel_pairs != num_channels {
+					outputs.get_mut(num_channels - 1)[sample_idx] =
+						volume_gain * non_finite_to_zero(inputs.get(num_channels - 1)[sample_idx]);
+				}
+
+				for index in num_channels..num_outputs {
+					outputs.get_mut(index)[sample_idx] = F::zero();
+				}
 			}
 
-			for index in num_channels..num_outputs {
-				outputs.get_mut(index)[sample_idx] = F::zero();
+			for channel in 0..echo_channels {
+				let output = &mut outputs.get_mut(channel)[sample_idx];
+				// Stay in f64 through the ring buffer and wet/dry mix so `process_f64` keeps
+				// the extra precision it advertises via `f64_precision: true`; only the final
+				// store back into `output` narrows to `F`, same as any f32 stream would anyway.
+				let dry = output.to_f64().unwrap_or(0.0);
+				let delay_buffer = &mut self.delay_buffers[channel];
+				let delayed = read_delay_line(delay_buffer, self.delay_write_pos, delay_samples);
+
+				delay_buffer[self.delay_write_pos] = dry + delayed * feedback;
+				let wet_sample = (1.0 - wet) * dry + wet * delayed;
+				*output = f64_to_float(wet_sample);
+
+				let abs_sample = (wet_sample.abs()) as f32;
+				let squared_sample = (wet_sample * wet_sample) as f32;
+				// The peak/RMS meter only has "Peak L/R" and "RMS L/R" parameters, so channels
+				// beyond the first pair (LCR/Quad/5.1 centre, rear and LFE channels) are not
+				// metered; a surround mix can clip on those without the host seeing it here.
+				if channel == 0 {
+					peak_left = if abs_sample > peak_left {
+						abs_sample
+					} else {
+						peak_left * peak_decay
+					};
+					mean_square_left += (squared_sample - mean_square_left) * rms_smoothing;
+				} else if channel == 1 {
+					peak_right = if abs_sample > peak_right {
+						abs_sample
+					} else {
+						peak_right * peak_decay
+					};
+					mean_square_right += (squared_sample - mean_square_right) * rms_smoothing;
+				}
 			}
+			self.delay_write_pos = (self.delay_write_pos + 1) % self.delay_buffers[0].len();
 		}
+
+		self.current_volume = volume;
+		self.current_pan = pan;
+
+		let mut storage = self.parameters.storage.write().unwrap();
+		storage.peak_left = peak_left;
+		storage.peak_right = peak_right;
+		storage.rms_left = mean_square_left.sqrt();
+		storage.rms_right = mean_square_right.sqrt();
 	}
 }
 
 impl Plugin for FaderEffect {
+	/// `vst-0.3`'s `VSTPluginMain` entry point calls `get_info` exactly once, at
+	/// construction, and bakes the resulting `inputs`/`outputs` into the host's
+	/// `AEffect`; neither VST2 nor this binding offers a way to renegotiate channel
+	/// count afterwards. That call sees a freshly built instance whose "Layout" is still
+	/// the Stereo default, so the plugin always reports 2 inputs and 2 outputs; setting
+	/// "Layout" later does not change the host's I/O counts, and therefore does not
+	/// change `num_channels` in `process_internal`.
 	fn get_info(&self) -> Info {
+		let layout = self.parameters.storage.read().unwrap().layout;
+		let channels = SPEAKER_LAYOUTS[speaker_layout_index(layout)].azimuths.len() as i32;
 		Info {
 			name: "jsFader (Rust Edition)".to_string(),
 			vendor: "jsPlugs".to_string(),
 			unique_id: 0x6a73_4661 ^ 0x0000_ffff,
 			version: 1,
-			inputs: 2,
-			outputs: 2,
-			parameters: 4,
+			inputs: channels,
+			outputs: channels,
+			parameters: 14,
 			category: Category::Effect,
 			f64_precision: true,
 			silent_when_stopped: true,
@@ -123,6 +287,28 @@ impl Plugin for FaderEffect {
 		}
 	}
 
+	fn set_sample_rate(&mut self, rate: f32) {
+		self.sample_rate = rate;
+		self.delay_buffers = new_delay_buffers(rate);
+		self.delay_write_pos = 0;
+	}
+
+	fn resume(&mut self) {
+		let mut storage = self.parameters.storage.write().unwrap();
+		self.current_volume = storage.volume;
+		self.current_pan = storage.pan;
+		storage.peak_left = 0.0;
+		storage.peak_right = 0.0;
+		storage.rms_left = 0.0;
+		storage.rms_right = 0.0;
+		drop(storage);
+
+		for buffer in &mut self.delay_buffers {
+			buffer.iter_mut().for_each(|sample| *sample = 0.0);
+		}
+		self.delay_write_pos = 0;
+	}
+
 	fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
 		self.process_internal(buffer);
 	}
@@ -144,6 +330,16 @@ struct FaderEffectParameterStorage {
 	pan: f32,
 	pan_taper: f32,
 	pan_law: f32,
+	pan_mode: f32,
+	tau: f32,
+	delay_time: f32,
+	feedback: f32,
+	mix: f32,
+	peak_left: f32,
+	peak_right: f32,
+	rms_left: f32,
+	rms_right: f32,
+	layout: f32,
 }
 
 struct FaderEffectParameters {
@@ -158,6 +354,16 @@ impl Default for FaderEffectParameters {
 				pan: 0.5,
 				pan_taper: 0.0,
 				pan_law: 0.5,
+				pan_mode: 0.0,
+				tau: 0.02,
+				delay_time: 0.15,
+				feedback: 0.35,
+				mix: 0.0,
+				peak_left: 0.0,
+				peak_right: 0.0,
+				rms_left: 0.0,
+				rms_right: 0.0,
+				layout: 0.0,
 			}),
 		}
 	}
@@ -171,6 +377,16 @@ impl PluginParameters for FaderEffectParameters {
 			1 => storage.pan,
 			2 => storage.pan_taper,
 			3 => storage.pan_law,
+			4 => storage.pan_mode,
+			5 => storage.tau,
+			6 => storage.delay_time,
+			7 => storage.feedback,
+			8 => storage.mix,
+			9 => storage.peak_left.min(1.0),
+			10 => storage.peak_right.min(1.0),
+			11 => storage.rms_left.min(1.0),
+			12 => storage.rms_right.min(1.0),
+			13 => storage.layout,
 			_ => {
 				// release lock before panicking!
 				drop(storage);
@@ -186,6 +402,14 @@ impl PluginParameters for FaderEffectParameters {
 			1 => storage.pan = value,
 			2 => storage.pan_taper = ((value * 2.0) as i32 as f32).max(0.0).min(1.0),
 			3 => storage.pan_law = ((value * 3.0) as i32 as f32 / 2.0).max(0.0).min(1.0),
+			4 => storage.pan_mode = ((value * 2.0) as i32 as f32).max(0.0).min(1.0),
+			5 => storage.tau = value,
+			6 => storage.delay_time = value,
+			7 => storage.feedback = value.max(0.0).min(0.99),
+			8 => storage.mix = value,
+			// peak/RMS meters are read-only outputs
+			9 | 10 | 11 | 12 => {}
+			13 => storage.layout = ((value * 4.0) as i32 as f32 / 3.0).max(0.0).min(1.0),
 			_ => {
 				// release lock before panicking!
 				drop(storage);
@@ -199,11 +423,7 @@ impl PluginParameters for FaderEffectParameters {
 			0 => {
 				let volume = self.storage.read().unwrap().volume;
 				let gain = lookup_interpolated(&*VOLUME_LUT, volume);
-				if gain < 1e-5 {
-					"-inf dB".to_string()
-				} else {
-					format!("{:+.1} dB", 20.0 * gain.log10())
-				}
+				amplitude_to_db_text(gain)
 			}
 			1 => {
 				let pan = (200.0 * (self.storage.read().unwrap().pan - 0.5)).round() as i32;
@@ -234,6 +454,36 @@ impl PluginParameters for FaderEffectParameters {
 					_ => "6 dB".to_string(),
 				}
 			}
+			4 => {
+				let index = (self.storage.read().unwrap().pan_mode * 2.0) as i32;
+				match index {
+					0 => "Balance".to_string(),
+					_ => "Equal Power".to_string(),
+				}
+			}
+			5 => {
+				let tau = self.storage.read().unwrap().tau;
+				format!("{:.1} ms", tau * 1000.0)
+			}
+			6 => {
+				let delay_time = self.storage.read().unwrap().delay_time;
+				format!("{:.0} ms", delay_time * MAX_DELAY_SECONDS * 1000.0)
+			}
+			7 => format!("{:.0} %", self.storage.read().unwrap().feedback * 100.0),
+			8 => format!("{:.0} %", self.storage.read().unwrap().mix * 100.0),
+			9 => amplitude_to_db_text(self.storage.read().unwrap().peak_left),
+			10 => amplitude_to_db_text(self.storage.read().unwrap().peak_right),
+			11 => amplitude_to_db_text(self.storage.read().unwrap().rms_left),
+			12 => amplitude_to_db_text(self.storage.read().unwrap().rms_right),
+			13 => {
+				let index = speaker_layout_index(self.storage.read().unwrap().layout);
+				match index {
+					0 => "Stereo".to_string(),
+					1 => "LCR".to_string(),
+					2 => "Quad".to_string(),
+					_ => "5.1".to_string(),
+				}
+			}
 			_ => panic!("invalid parameter index!"),
 		}
 	}
@@ -244,6 +494,16 @@ impl PluginParameters for FaderEffectParameters {
 			1 => "Pan",
 			2 => "Pan Taper",
 			3 => "Pan Law",
+			4 => "Pan Mode",
+			5 => "Smoothing",
+			6 => "Delay Time",
+			7 => "Feedback",
+			8 => "Mix",
+			9 => "Peak L",
+			10 => "Peak R",
+			11 => "RMS L",
+			12 => "RMS R",
+			13 => "Layout",
 			_ => panic!("invalid parameter index!"),
 		}
 		.to_string()
@@ -271,6 +531,55 @@ const PAN_LAWS: [f32; 3] = {
 	laws
 };
 
+/// A speaker layout: per-channel azimuth in degrees (0 = front centre, negative = left,
+/// positive = right) and whether the channel is a non-directional LFE that ignores pan.
+struct SpeakerLayout {
+	azimuths: &'static [f32],
+	lfe: &'static [bool],
+}
+
+const STEREO_LAYOUT: SpeakerLayout = SpeakerLayout {
+	azimuths: &[-90.0, 90.0],
+	lfe: &[false, false],
+};
+const LCR_LAYOUT: SpeakerLayout = SpeakerLayout {
+	azimuths: &[-90.0, 0.0, 90.0],
+	lfe: &[false, false, false],
+};
+const QUAD_LAYOUT: SpeakerLayout = SpeakerLayout {
+	azimuths: &[-45.0, 45.0, -135.0, 135.0],
+	lfe: &[false, false, false, false],
+};
+const FIVE_POINT_ONE_LAYOUT: SpeakerLayout = SpeakerLayout {
+	azimuths: &[-30.0, 30.0, 0.0, 0.0, -110.0, 110.0],
+	lfe: &[false, false, false, true, false, false],
+};
+
+static SPEAKER_LAYOUTS: [SpeakerLayout; 4] =
+	[STEREO_LAYOUT, LCR_LAYOUT, QUAD_LAYOUT, FIVE_POINT_ONE_LAYOUT];
+
+fn speaker_layout_index(layout: f32) -> usize {
+	((layout * 4.0) as usize).min(SPEAKER_LAYOUTS.len() - 1)
+}
+
+/// Picks the `SPEAKER_LAYOUTS` entry whose azimuth table matches the channel count the host
+/// actually negotiated, regardless of what the "Layout" parameter has been set to since
+/// construction. A count that matches no entry gets the widest layout (5.1).
+fn speaker_layout_for_channel_count(num_channels: usize) -> &'static SpeakerLayout {
+	SPEAKER_LAYOUTS
+		.iter()
+		.find(|layout| layout.azimuths.len() == num_channels)
+		.unwrap_or(&SPEAKER_LAYOUTS[SPEAKER_LAYOUTS.len() - 1])
+}
+
+/// Equal-power gain for a speaker at `speaker_azimuth` given a pan ring position of
+/// `pan_azimuth`, both in degrees, using `pan_law` the same way the stereo balance path does.
+fn ring_pan_gain(pan_law: &[f32], pan_azimuth: f32, speaker_azimuth: f32) -> f32 {
+	let diff = (pan_azimuth - speaker_azimuth).abs() % 360.0;
+	let circular_distance = if diff > 180.0 { 360.0 - diff } else { diff };
+	lookup_interpolated(pan_law, circular_distance / 180.0)
+}
+
 lazy_static! {
 	static ref VOLUME_LUT: [f32; 10] = {
 		let mut lut = [0.0f32; 10];
@@ -316,3 +625,9 @@ fn non_finite_to_zero<F: num_traits::Float>(value: F) -> F {
 		F::zero()
 	}
 }
+
+/// Narrows an f64 echo-stage sample to the buffer's sample type; lossless for `F = f64` and
+/// the same narrowing an f32 stream would already need for `F = f32`.
+fn f64_to_float<F: num_traits::Float>(value: f64) -> F {
+	num_traits::NumCast::from(value).unwrap_or_else(F::zero)
+}